@@ -1,21 +1,84 @@
 use std::time::Duration;
-use std::{env, io};
 
 use cached::instant::Instant;
 use chrono::{DateTime, Utc};
-use log::{debug, error, warn};
-use oauth2::basic::{BasicClient, BasicTokenResponse};
-use oauth2::reqwest::async_http_client;
-use oauth2::url::Url;
-use oauth2::{AuthUrl, ClientId, ClientSecret, Scope, TokenResponse, TokenUrl};
+use metrics::{counter, histogram};
 use sqlx::PgPool;
+use tokio::time::sleep;
+use tracing::{debug, error, warn};
 
 use crate::calendar::models::Event;
+use crate::calendar::token_manager::TokenManager;
 
+#[derive(Clone)]
 pub(in crate::calendar) struct APIRequestor {
     client: reqwest::Client,
     pool: PgPool,
-    oauth_token: Option<BasicTokenResponse>,
+    token_manager: TokenManager,
+}
+
+/// Conditional-request validators for a single room's calendar, as last seen
+/// on a `200` response from ConnectUM.
+#[derive(Debug, Default)]
+struct CalendarValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// The subset of an event's fields that identifies it across scrapes, used to
+/// diff a freshly fetched calendar against what's already stored for a room
+/// instead of dropping and reinserting everything. `title` has to stay part
+/// of the identity (see [`Event::upsert`]): ConnectUM has no stable upstream
+/// event id, and two distinct bookings can share a start/end, so dropping
+/// `title` would let one overwrite the other instead of being kept alongside
+/// it. The cost is that a rename is diffed as delete+insert, not a row
+/// update.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct EventIdentity {
+    start_at: DateTime<Utc>,
+    end_at: DateTime<Utc>,
+    title: String,
+}
+
+fn event_identity(event: &Event) -> EventIdentity {
+    EventIdentity {
+        start_at: event.start_at,
+        end_at: event.end_at,
+        title: event.title.clone(),
+    }
+}
+
+/// An already-stored event, reduced to the columns needed to compute
+/// [`EventIdentity`]; we don't need the full row to decide what's stale.
+struct ExistingEvent {
+    start_at: DateTime<Utc>,
+    end_at: DateTime<Utc>,
+    title: String,
+}
+
+impl ExistingEvent {
+    fn identity(&self) -> EventIdentity {
+        EventIdentity {
+            start_at: self.start_at,
+            end_at: self.end_at,
+            title: self.title.clone(),
+        }
+    }
+}
+
+impl CalendarValidators {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header_str = |name: reqwest::header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header_str(reqwest::header::ETAG),
+            last_modified: header_str(reqwest::header::LAST_MODIFIED),
+        }
+    }
 }
 
 impl From<&PgPool> for APIRequestor {
@@ -34,30 +97,70 @@ impl From<&PgPool> for APIRequestor {
         Self {
             client,
             pool: pool.clone(),
-            oauth_token: None,
+            token_manager: TokenManager::new(),
         }
     }
 }
 
+/// Maximum number of attempts `fetch_calendar` makes against a single room
+/// before giving up and propagating the last error.
+const MAX_FETCH_ATTEMPTS: u32 = 5;
+/// Starting point for the exponential backoff used on `5xx`/connection
+/// errors (and on a `429` without `Retry-After`); doubled after every `429`,
+/// `5xx` or connection-error retry, not after the `401` retry, and randomized
+/// by [`with_jitter`] for `5xx`/connection errors.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// ConnectUM answered with a non-success status that isn't worth retrying
+/// (or that exhausted its retries). `status` is surfaced so the scheduler can
+/// tell a definitive client error -- e.g. a `404`/`410` for a room ConnectUM
+/// no longer knows about -- from a transient failure and back off
+/// accordingly instead of treating every failure the same (see
+/// `scheduler::failure_backoff`).
+#[derive(Debug, thiserror::Error)]
+#[error("ConnectUM responded with {status}")]
+pub(in crate::calendar) struct ConnectumStatusError {
+    pub(in crate::calendar) status: reqwest::StatusCode,
+}
+
+/// Adds up to ~20% random jitter to `base`, seeded from the wall clock so we
+/// don't need an RNG dependency just for retry spacing.
+fn with_jitter(base: Duration) -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let max_jitter_ms = (base.as_millis() as u64 / 5).max(1);
+    base + Duration::from_millis(u64::from(nanos) % max_jitter_ms)
+}
+
+/// Parses a `Retry-After` header in either form allowed by the HTTP spec: a
+/// number of seconds, or an HTTP-date to wait until.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let until = httpdate::parse_http_date(value).ok()?;
+    until.duration_since(std::time::SystemTime::now()).ok()
+}
+
 impl APIRequestor {
-    pub(crate) async fn refresh(&mut self, id: &str) -> Result<(), crate::BoxedError> {
+    #[tracing::instrument(skip(self), fields(room_code = %id, events = tracing::field::Empty))]
+    pub(crate) async fn refresh(&self, id: &str) -> Result<(), crate::BoxedError> {
         let sync_start = Utc::now();
-        let token = self.try_unwrap_or_refresh_token().await?;
-        let start = Instant::now();
-        let url = format!("https://campus.tum.de/tumonline/co/connectum/api/rooms/{id}/calendars");
-        let events: Vec<Event> = self
-            .client
-            .get(url)
-            .bearer_auth(token)
-            .send()
-            .await?
-            .json()
-            .await?;
-        debug!(
-            "finished fetching for {id}: {cnt} calendar events in {elapsed:?}",
-            cnt = events.len(),
-            elapsed = start.elapsed()
-        );
+        let validators = self.load_calendar_validators(id).await?;
+        let response = self.fetch_calendar(id, &validators).await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!("unchanged since last scrape (304), skipping decode and store");
+            counter!("calendar_scrape_not_modified_total").increment(1);
+            self.touch_last_calendar_scrape_at(id, &sync_start).await?;
+            return Ok(());
+        }
+        let validators = CalendarValidators::from_headers(response.headers());
+        let events: Vec<Event> = response.json().await?;
+        tracing::Span::current().record("events", events.len());
+        debug!(cnt = events.len(), "finished fetching calendar events");
+        counter!("calendar_scrape_events_fetched_total").increment(events.len() as u64);
         let events = events
             .into_iter()
             .map(|mut e| {
@@ -65,55 +168,128 @@ impl APIRequestor {
                 e
             })
             .collect::<Vec<Event>>();
-        self.store(&events, &sync_start, id).await?;
+        self.store(&events, &sync_start, id, &validators).await?;
         Ok(())
     }
-    async fn try_unwrap_or_refresh_token(&mut self) -> Result<String, crate::BoxedError> {
-        match &self.oauth_token {
-            None => {
-                debug!("oauth token not present");
-                self.oauth_token = Some(Self::fetch_new_oauth_token().await?);
+
+    /// Fetches a room's calendar, retrying transient failures instead of
+    /// aborting the whole room on the first hiccup: a `401` forces one token
+    /// refresh and retry, a `429` waits out its `Retry-After`, and `5xx`/
+    /// connection errors back off exponentially with jitter. These only
+    /// propagate once `MAX_FETCH_ATTEMPTS` is exhausted; any other non-success
+    /// status (or a second `401`) is returned as a [`ConnectumStatusError`] at
+    /// once.
+    async fn fetch_calendar(
+        &self,
+        id: &str,
+        validators: &CalendarValidators,
+    ) -> Result<reqwest::Response, crate::BoxedError> {
+        let url = format!("https://campus.tum.de/tumonline/co/connectum/api/rooms/{id}/calendars");
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut forced_token_refresh = false;
+        for attempt in 1..=MAX_FETCH_ATTEMPTS {
+            let attempt_start = Instant::now();
+            let token = self.token_manager.access_token().await?;
+            let mut request = self.client.get(&url).bearer_auth(token);
+            if let Some(etag) = &validators.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
             }
-            Some(token) => {
-                let expires_in = token.expires_in();
-                debug!("oauth expires in {expires_in:?}");
-                if !expires_in.is_some_and(|e| e > Duration::from_secs(10)) {
-                    self.oauth_token = Some(Self::fetch_new_oauth_token().await?);
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+            let result = request.send().await;
+            let last_attempt = attempt == MAX_FETCH_ATTEMPTS;
+            match result {
+                Ok(response)
+                    if response.status().is_success()
+                        || response.status() == reqwest::StatusCode::NOT_MODIFIED =>
+                {
+                    histogram!("calendar_scrape_request_duration_seconds")
+                        .record(attempt_start.elapsed());
+                    return Ok(response);
+                }
+                Ok(response)
+                    if response.status() == reqwest::StatusCode::UNAUTHORIZED
+                        && !forced_token_refresh
+                        && !last_attempt =>
+                {
+                    warn!("received 401 from ConnectUM, forcing a token refresh and retrying once");
+                    forced_token_refresh = true;
+                    counter!("calendar_scrape_retries_total").increment(1);
+                    self.token_manager.force_refresh(&token).await?;
+                }
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && !last_attempt => {
+                    let wait = retry_after(response.headers()).unwrap_or(backoff);
+                    warn!("rate limited by ConnectUM (429), waiting {wait:?} before retrying");
+                    counter!("calendar_scrape_retries_total").increment(1);
+                    sleep(wait).await;
+                    backoff *= 2;
+                }
+                Ok(response) if response.status().is_server_error() && !last_attempt => {
+                    let wait = with_jitter(backoff);
+                    warn!(
+                        "ConnectUM returned {status}, retrying in {wait:?} ({attempt}/{MAX_FETCH_ATTEMPTS})",
+                        status = response.status()
+                    );
+                    counter!("calendar_scrape_retries_total").increment(1);
+                    sleep(wait).await;
+                    backoff *= 2;
                 }
+                Ok(response) => {
+                    return Err(ConnectumStatusError {
+                        status: response.status(),
+                    }
+                    .into());
+                }
+                Err(e) if !last_attempt => {
+                    let wait = with_jitter(backoff);
+                    warn!("request to ConnectUM failed: {e:?}, retrying in {wait:?} ({attempt}/{MAX_FETCH_ATTEMPTS})");
+                    counter!("calendar_scrape_retries_total").increment(1);
+                    sleep(wait).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e.into()),
             }
-        };
-
-        Ok(self
-            .oauth_token
-            .as_ref()
-            .expect("the token has been set in the last step")
-            .access_token()
-            .secret()
-            .clone())
+        }
+        unreachable!("the last attempt always returns instead of falling through the loop")
     }
 }
 
 impl APIRequestor {
+    #[tracing::instrument(skip_all, fields(room_code = %id))]
     async fn store(
         &self,
         events: &[Event],
         last_calendar_scrape_at: &DateTime<Utc>,
         id: &str,
+        validators: &CalendarValidators,
     ) -> Result<(), crate::BoxedError> {
+        let start = Instant::now();
         // insert into db
         let mut tx = self.pool.begin().await?;
-        if let Err(e) = self.delete_events(&mut tx, id).await {
-            error!("could not delete existing events because {e:?}");
-            tx.rollback().await?;
-            return Err(e.into());
-        }
+        let existing_identities = match self.reconcile_stale_events(&mut tx, id, events).await {
+            Ok(existing_identities) => existing_identities,
+            Err(e) => {
+                error!("could not reconcile existing events because {e:?}");
+                counter!("calendar_scrape_store_failures_total").increment(1);
+                tx.rollback().await?;
+                return Err(e.into());
+            }
+        };
+        let mut any_upsert_failed = false;
         for (i, event) in events.iter().enumerate() {
-            // conflicts cannot occur because all values for said room were dropped
-            if let Err(e) = event.store(&mut tx).await {
+            // an event is unchanged only if its full identity (including
+            // title) is already stored; skipping those is what avoids
+            // rewriting every row on every scrape
+            if existing_identities.contains(&event_identity(event)) {
+                continue;
+            }
+            if let Err(e) = event.upsert(&mut tx).await {
                 warn!(
-                    "ignoring insert {event:?} ({i}/{total}) because {e:?}",
+                    "ignoring upsert {event:?} ({i}/{total}) because {e:?}",
                     total = events.len()
                 );
+                any_upsert_failed = true;
             }
         }
         if let Err(e) = self
@@ -121,52 +297,145 @@ impl APIRequestor {
             .await
         {
             error!("could not update last_calendar_scrape_at because {e:?}");
+            counter!("calendar_scrape_store_failures_total").increment(1);
+            tx.rollback().await?;
+            return Err(e.into());
+        }
+        // if an event was dropped, the validators we just saw must not be
+        // persisted: they'd make the *next* scrape send If-None-Match/
+        // If-Modified-Since for a calendar we never fully stored, so a 304
+        // would keep hiding the event we failed to write instead of retrying it
+        let validators_to_persist = if any_upsert_failed {
+            warn!("not persisting calendar validators: at least one event failed to store");
+            &CalendarValidators::default()
+        } else {
+            validators
+        };
+        if let Err(e) = self
+            .update_calendar_validators(&mut tx, id, validators_to_persist)
+            .await
+        {
+            error!("could not update calendar validators because {e:?}");
+            counter!("calendar_scrape_store_failures_total").increment(1);
             tx.rollback().await?;
             return Err(e.into());
         }
         tx.commit().await?;
-        debug!("finished inserting into the db for {id}");
+        debug!(elapsed = ?start.elapsed(), "finished inserting into the db");
         Ok(())
     }
 
-    async fn fetch_new_oauth_token() -> Result<BasicTokenResponse, crate::BoxedError> {
-        let client_id = env::var("CONNECTUM_OAUTH_CLIENT_ID")
-            .map_err(|e| {
-                error!("CONNECTUM_OAUTH_CLIENT_ID needs to be set: {e:?}");
-                io::Error::other("please configure the environment variable CONNECTUM_OAUTH_CLIENT_ID to use this endpoint")
-            })?
-            .trim().into();
-        let client_secret = env::var("CONNECTUM_OAUTH_CLIENT_SECRET")
-            .map_err(|e| {
-                error!("CONNECTUM_OAUTH_CLIENT_SECRET needs to be set: {e:?}");
-                io::Error::other("please configure the environment variable CONNECTUM_OAUTH_CLIENT_SECRET to use this endpoint")
-            })?
-            .trim().into();
-
-        // for urls see https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/.well-known/openid-configuration
-        let auth_url = Url::parse("https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/protocol/openid-connect/auth")?;
-        let token_url = Url::parse("https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/protocol/openid-connect/token")?;
-
-        let token = BasicClient::new(
-            ClientId::new(client_id),
-            Some(ClientSecret::new(client_secret)),
-            AuthUrl::from_url(auth_url),
-            Some(TokenUrl::from_url(token_url)),
+    async fn load_calendar_validators(
+        &self,
+        id: &str,
+    ) -> Result<CalendarValidators, crate::BoxedError> {
+        let validators = sqlx::query_as!(
+            CalendarValidators,
+            "SELECT calendar_etag AS etag, calendar_last_modified AS last_modified FROM en WHERE key=$1",
+            id
         )
-        .exchange_client_credentials()
-        .add_scope(Scope::new("connectum-rooms.read".into()))
-        .request_async(async_http_client)
-        .await;
-        Ok(token?)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(validators.unwrap_or_default())
+    }
+
+    async fn touch_last_calendar_scrape_at(
+        &self,
+        id: &str,
+        last_calendar_scrape_at: &DateTime<Utc>,
+    ) -> Result<(), crate::BoxedError> {
+        let mut tx = self.pool.begin().await?;
+        self.update_last_calendar_scrape_at(&mut tx, id, last_calendar_scrape_at)
+            .await?;
+        tx.commit().await?;
+        Ok(())
     }
-    async fn delete_events(
+
+    async fn update_calendar_validators(
         &self,
         tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
         id: &str,
+        validators: &CalendarValidators,
     ) -> Result<sqlx::postgres::PgQueryResult, sqlx::Error> {
-        sqlx::query!(r#"DELETE FROM calendar WHERE room_code = $1"#, id)
-            .execute(&mut **tx)
-            .await
+        sqlx::query!(
+            "UPDATE en SET calendar_etag = $1, calendar_last_modified = $2 WHERE key=$3",
+            validators.etag,
+            validators.last_modified,
+            id
+        )
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE de SET calendar_etag = $1, calendar_last_modified = $2 WHERE key=$3",
+            validators.etag,
+            validators.last_modified,
+            id
+        )
+        .execute(&mut **tx)
+        .await
+    }
+
+    /// Deletes exactly the events for `id` that are no longer present in
+    /// `fetched`, identified by [`EventIdentity`], and returns the identities
+    /// that are still present, so `store` can tell an unchanged event from a
+    /// new one instead of rewriting every row on every scrape.
+    async fn reconcile_stale_events(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: &str,
+        fetched: &[Event],
+    ) -> Result<std::collections::HashSet<EventIdentity>, sqlx::Error> {
+        let existing = self.load_existing_events(tx, id).await?;
+        let fetched_identities: std::collections::HashSet<EventIdentity> =
+            fetched.iter().map(event_identity).collect();
+        let mut existing_identities = std::collections::HashSet::with_capacity(existing.len());
+        let mut stale = Vec::new();
+        for event in existing {
+            let identity = event.identity();
+            if fetched_identities.contains(&identity) {
+                existing_identities.insert(identity);
+            } else {
+                stale.push(event);
+            }
+        }
+        if !stale.is_empty() {
+            debug!("deleting {cnt} stale events for {id}", cnt = stale.len());
+            for event in stale {
+                self.delete_event(tx, id, &event).await?;
+            }
+        }
+        Ok(existing_identities)
+    }
+
+    async fn load_existing_events(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: &str,
+    ) -> Result<Vec<ExistingEvent>, sqlx::Error> {
+        sqlx::query_as!(
+            ExistingEvent,
+            "SELECT start_at, end_at, title FROM calendar WHERE room_code = $1",
+            id
+        )
+        .fetch_all(&mut **tx)
+        .await
+    }
+
+    async fn delete_event(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: &str,
+        event: &ExistingEvent,
+    ) -> Result<sqlx::postgres::PgQueryResult, sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM calendar WHERE room_code = $1 AND start_at = $2 AND end_at = $3 AND title = $4",
+            id,
+            event.start_at,
+            event.end_at,
+            event.title
+        )
+        .execute(&mut **tx)
+        .await
     }
     async fn update_last_calendar_scrape_at(
         &self,
@@ -0,0 +1,48 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// A single calendar entry for a room, as returned by ConnectUM.
+///
+/// `(room_code, start_at, end_at, title)` is this event's stable identity
+/// across scrapes (see the `calendar_event_identity_unique` migration's
+/// unique constraint).
+/// ConnectUM carries no stable upstream event id, and two distinct bookings
+/// for the same room can legitimately share a start/end (e.g. a shared
+/// lecture hall slot covered by two separate entries), so `title` has to stay
+/// part of the identity: dropping it would let the second event's upsert
+/// overwrite the first instead of being stored alongside it. The cost is that
+/// a rename is diffed as delete+insert rather than an in-place title update.
+#[derive(Debug, Deserialize)]
+pub(in crate::calendar) struct Event {
+    #[serde(skip)]
+    pub(in crate::calendar) room_code: String,
+    pub(in crate::calendar) start_at: DateTime<Utc>,
+    pub(in crate::calendar) end_at: DateTime<Utc>,
+    pub(in crate::calendar) title: String,
+}
+
+impl Event {
+    /// Inserts this event, targeting the unique index on
+    /// `(room_code, start_at, end_at, title)` added alongside incremental
+    /// reconciliation. A conflict only occurs if the exact same event is
+    /// upserted twice (e.g. a retried store), in which case there's nothing
+    /// to change, so it's a no-op rather than an update.
+    pub(in crate::calendar) async fn upsert(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<sqlx::postgres::PgQueryResult, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO calendar (room_code, start_at, end_at, title)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (room_code, start_at, end_at, title) DO NOTHING
+            "#,
+            self.room_code,
+            self.start_at,
+            self.end_at,
+            self.title
+        )
+        .execute(&mut **tx)
+        .await
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use sqlx::PgPool;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::{sleep_until, Instant};
+use tracing::{error, info};
+
+use crate::calendar::connectum::{APIRequestor, ConnectumStatusError};
+
+/// Maximum number of calendar refreshes allowed to run concurrently against
+/// ConnectUM.
+const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+/// Minimum spacing enforced between the start of two room refreshes,
+/// independent of how many are in flight; retries inside a single refresh are
+/// not spaced by it. Acts as a token-bucket rate limit against
+/// `campus.tum.de` so a burst of stale rooms can't hammer ConnectUM.
+const DEFAULT_MIN_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+/// How many of the most-stale rooms `run_once` pulls per call.
+const DEFAULT_BATCH_SIZE: i64 = 200;
+/// Backoff applied after a room's first consecutive refresh failure; doubles
+/// per further consecutive failure, capped at `MAX_FAILURE_BACKOFF`.
+const INITIAL_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+/// Upper bound on how long a persistently-failing room is excluded from
+/// selection, so it's parked, not abandoned: it's still retried eventually.
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Background sync loop over [`APIRequestor::refresh`]: each call to
+/// [`Scheduler::run_once`] selects the most-stale *and eligible* rooms and
+/// refreshes them with bounded concurrency and a shared rate limit. `refresh`
+/// already retries transient HTTP failures internally with its own backoff;
+/// a room that still fails here is re-enqueued with its own exponential
+/// backoff (see [`record_refresh_failure`]) instead of staying pinned at the
+/// front of every batch with `last_calendar_scrape_at` stuck at NULL.
+pub(in crate::calendar) struct Scheduler {
+    pool: PgPool,
+    requestor: APIRequestor,
+    max_in_flight: usize,
+    min_request_interval: Duration,
+    batch_size: i64,
+}
+
+impl Scheduler {
+    pub(in crate::calendar) fn new(pool: PgPool) -> Self {
+        let requestor = APIRequestor::from(&pool);
+        Self {
+            pool,
+            requestor,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            min_request_interval: DEFAULT_MIN_REQUEST_INTERVAL,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    pub(in crate::calendar) fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    pub(in crate::calendar) fn with_min_request_interval(mut self, interval: Duration) -> Self {
+        self.min_request_interval = interval;
+        self
+    }
+
+    pub(in crate::calendar) fn with_batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Selects the `batch_size` rooms with the oldest (or missing)
+    /// `last_calendar_scrape_at` among those not currently in failure backoff
+    /// and refreshes them concurrently, bounded by
+    /// `max_in_flight` workers sharing one rate-limited `APIRequestor`.
+    pub(in crate::calendar) async fn run_once(&self) -> Result<(), crate::BoxedError> {
+        let room_keys = self.select_stale_rooms().await?;
+        info!("scheduler: refreshing {} stale rooms", room_keys.len());
+        let semaphore = Arc::new(Semaphore::new(self.max_in_flight));
+        let rate_limiter = Arc::new(RateLimiter::new(self.min_request_interval));
+        let mut workers = Vec::with_capacity(room_keys.len());
+        for room_key in room_keys {
+            let semaphore = Arc::clone(&semaphore);
+            let rate_limiter = Arc::clone(&rate_limiter);
+            let requestor = self.requestor.clone();
+            let pool = self.pool.clone();
+            workers.push(tokio::spawn(async move {
+                let _permit = semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                rate_limiter.acquire().await;
+                refresh_room(&requestor, &pool, &room_key).await;
+            }));
+        }
+        for worker in workers {
+            if let Err(e) = worker.await {
+                error!("scheduler worker panicked: {e:?}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn select_stale_rooms(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT key FROM en
+            WHERE calendar_scrape_next_attempt_at IS NULL OR calendar_scrape_next_attempt_at <= now()
+            ORDER BY last_calendar_scrape_at NULLS FIRST
+            LIMIT $1
+            "#,
+            self.batch_size
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+/// Refreshes a single room. `APIRequestor::refresh` already retries
+/// transient HTTP failures with its own backoff; a failure that survives
+/// those retries is recorded via [`record_refresh_failure`] so the room
+/// rotates to the back of the queue with its own backoff instead of staying
+/// pinned at the front with `last_calendar_scrape_at` stuck at NULL. A
+/// success clears any backoff the room had accumulated.
+#[tracing::instrument(skip(requestor, pool))]
+async fn refresh_room(requestor: &APIRequestor, pool: &PgPool, room_key: &str) {
+    match requestor.refresh(room_key).await {
+        Ok(()) => {
+            if let Err(e) = record_refresh_success(pool, room_key).await {
+                error!("could not clear refresh backoff for {room_key}: {e:?}");
+            }
+        }
+        Err(e) => {
+            let status = e.downcast_ref::<ConnectumStatusError>().map(|e| e.status);
+            error!("refresh of {room_key} failed, backing off before it's retried: {e:?}");
+            if let Err(e) = record_refresh_failure(pool, room_key, status).await {
+                error!("could not record refresh failure for {room_key}: {e:?}");
+            }
+        }
+    }
+}
+
+/// How long to exclude a room from selection after another consecutive
+/// refresh failure. ConnectUM answering with a client error other than `401`/
+/// `429` (both already retried inside `fetch_calendar`) means the room itself
+/// is the problem -- e.g. a `404`/`410` for a room ConnectUM no longer knows
+/// about -- so there's nothing to grow into: park it at the cap right away
+/// instead of waiting out the usual exponential ramp.
+fn failure_backoff(failure_count: i32, status: Option<reqwest::StatusCode>) -> Duration {
+    if status.is_some_and(|s| {
+        s.is_client_error()
+            && s != reqwest::StatusCode::UNAUTHORIZED
+            && s != reqwest::StatusCode::TOO_MANY_REQUESTS
+    }) {
+        return MAX_FAILURE_BACKOFF;
+    }
+    let exponent = failure_count.clamp(1, 7) as u32 - 1;
+    (INITIAL_FAILURE_BACKOFF * 2u32.pow(exponent)).min(MAX_FAILURE_BACKOFF)
+}
+
+async fn record_refresh_success(pool: &PgPool, room_key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE en SET calendar_scrape_failure_count = 0, calendar_scrape_next_attempt_at = NULL WHERE key = $1",
+        room_key
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query!(
+        "UPDATE de SET calendar_scrape_failure_count = 0, calendar_scrape_next_attempt_at = NULL WHERE key = $1",
+        room_key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn record_refresh_failure(
+    pool: &PgPool,
+    room_key: &str,
+    status: Option<reqwest::StatusCode>,
+) -> Result<(), sqlx::Error> {
+    let failure_count = sqlx::query_scalar!(
+        r#"
+        UPDATE en SET calendar_scrape_failure_count = calendar_scrape_failure_count + 1
+        WHERE key = $1
+        RETURNING calendar_scrape_failure_count
+        "#,
+        room_key
+    )
+    .fetch_one(pool)
+    .await?;
+    sqlx::query!(
+        "UPDATE de SET calendar_scrape_failure_count = calendar_scrape_failure_count + 1 WHERE key = $1",
+        room_key
+    )
+    .execute(pool)
+    .await?;
+    let next_attempt_at = Utc::now()
+        + chrono::Duration::from_std(failure_backoff(failure_count, status)).unwrap_or_default();
+    sqlx::query!(
+        "UPDATE en SET calendar_scrape_next_attempt_at = $1 WHERE key = $2",
+        next_attempt_at,
+        room_key
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query!(
+        "UPDATE de SET calendar_scrape_next_attempt_at = $1 WHERE key = $2",
+        next_attempt_at,
+        room_key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Minimal token-bucket rate limiter: guarantees at least `interval` between
+/// successive `acquire` calls, shared across all scheduler workers.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let wait_until = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        sleep_until(wait_until).await;
+    }
+}
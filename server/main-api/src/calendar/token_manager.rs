@@ -0,0 +1,115 @@
+use std::env;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use metrics::counter;
+use oauth2::basic::{BasicClient, BasicTokenResponse};
+use oauth2::reqwest::async_http_client;
+use oauth2::url::Url;
+use oauth2::{AuthUrl, ClientId, ClientSecret, Scope, TokenResponse, TokenUrl};
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+/// Shared, cheaply-cloneable cache for the ConnectUM OAuth token.
+///
+/// Every `APIRequestor` clone holds the same underlying cache, so concurrent
+/// refreshes single-flight the client-credentials exchange: the first caller
+/// to see a missing/expiring token holds the lock for the whole exchange,
+/// and everyone else just awaits the refreshed value instead of also hitting
+/// Keycloak. A failed exchange leaves the cache exactly as it was, so it
+/// never gets poisoned and the next caller simply retries.
+#[derive(Clone)]
+pub(in crate::calendar) struct TokenManager {
+    token: Arc<Mutex<Option<BasicTokenResponse>>>,
+}
+
+impl TokenManager {
+    pub(in crate::calendar) fn new() -> Self {
+        Self {
+            token: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub(in crate::calendar) async fn access_token(&self) -> Result<String, crate::BoxedError> {
+        let mut token = self.token.lock().await;
+        let needs_refresh = match &*token {
+            None => {
+                debug!("oauth token not present");
+                true
+            }
+            Some(token) => {
+                let expires_in = token.expires_in();
+                debug!("oauth expires in {expires_in:?}");
+                !expires_in.is_some_and(|e| e > Duration::from_secs(10))
+            }
+        };
+        if needs_refresh {
+            *token = Some(Self::fetch_new_oauth_token().await?);
+            counter!("calendar_scrape_token_refreshes_total").increment(1);
+        }
+
+        Ok(token
+            .as_ref()
+            .expect("the token has been set in the last step")
+            .access_token()
+            .secret()
+            .clone())
+    }
+
+    /// Exchanges a fresh token, bypassing the expiry check, unless someone
+    /// else already did so while we were waiting for the lock. `stale` is the
+    /// token the caller saw rejected with a `401`; if the cache no longer
+    /// holds it, another concurrent caller has already refreshed and we just
+    /// return their result instead of also hitting Keycloak.
+    pub(in crate::calendar) async fn force_refresh(
+        &self,
+        stale: &str,
+    ) -> Result<String, crate::BoxedError> {
+        let mut token = self.token.lock().await;
+        let already_refreshed = token
+            .as_ref()
+            .is_some_and(|token| token.access_token().secret() != stale);
+        if !already_refreshed {
+            *token = Some(Self::fetch_new_oauth_token().await?);
+            counter!("calendar_scrape_token_refreshes_total").increment(1);
+        }
+        Ok(token
+            .as_ref()
+            .expect("the token has been set in the last step")
+            .access_token()
+            .secret()
+            .clone())
+    }
+
+    async fn fetch_new_oauth_token() -> Result<BasicTokenResponse, crate::BoxedError> {
+        let client_id = env::var("CONNECTUM_OAUTH_CLIENT_ID")
+            .map_err(|e| {
+                error!("CONNECTUM_OAUTH_CLIENT_ID needs to be set: {e:?}");
+                io::Error::other("please configure the environment variable CONNECTUM_OAUTH_CLIENT_ID to use this endpoint")
+            })?
+            .trim().into();
+        let client_secret = env::var("CONNECTUM_OAUTH_CLIENT_SECRET")
+            .map_err(|e| {
+                error!("CONNECTUM_OAUTH_CLIENT_SECRET needs to be set: {e:?}");
+                io::Error::other("please configure the environment variable CONNECTUM_OAUTH_CLIENT_SECRET to use this endpoint")
+            })?
+            .trim().into();
+
+        // for urls see https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/.well-known/openid-configuration
+        let auth_url = Url::parse("https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/protocol/openid-connect/auth")?;
+        let token_url = Url::parse("https://campus.tum.de/tumonline/co/public/sec/auth/realms/CAMPUSonline/protocol/openid-connect/token")?;
+
+        let token = BasicClient::new(
+            ClientId::new(client_id),
+            Some(ClientSecret::new(client_secret)),
+            AuthUrl::from_url(auth_url),
+            Some(TokenUrl::from_url(token_url)),
+        )
+        .exchange_client_credentials()
+        .add_scope(Scope::new("connectum-rooms.read".into()))
+        .request_async(async_http_client)
+        .await;
+        Ok(token?)
+    }
+}